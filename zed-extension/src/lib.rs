@@ -1,12 +1,29 @@
-use zed_extension_api::{self as zed};
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use zed_extension_api::{self as zed, settings::LspSettings};
 
 const GITHUB_REPO: &str = "shopwareLabs/shopware-lsp";
 const BINARY_NAME: &str = "shopware-lsp";
+const SERVER_NAME: &str = "shopware-lsp";
+
+/// How long an installed version directory is kept around after it was last resolved.
+/// A fresh extension-host process has no record of which other worktrees are open and
+/// which versions they're pinned to, so cleanup can't rely on an in-memory "currently
+/// seen" set without risking deleting a still-open worktree's version out from under it.
+/// Retaining anything touched within this window (see `delete_obsolete_versions`) gives
+/// sibling worktrees a chance to "check in" again before their install is swept.
+const VERSION_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
 struct ShopwareExtension {
     cached_binary_path: Option<String>,
 }
 
+struct PlannedInstall {
+    version: String,
+    download_url: String,
+}
+
 impl ShopwareExtension {
     fn asset_suffix_for_platform(os: zed::Os, arch: zed::Architecture) -> Option<&'static str> {
         match (os, arch) {
@@ -14,15 +31,32 @@ impl ShopwareExtension {
             (zed::Os::Mac, zed::Architecture::Aarch64) => Some("darwin_arm64.zip"),
             (zed::Os::Linux, zed::Architecture::X8664) => Some("linux_amd64.zip"),
             (zed::Os::Linux, zed::Architecture::Aarch64) => Some("linux_arm64.zip"),
+            (zed::Os::Windows, zed::Architecture::X8664) => Some("windows_amd64.zip"),
+            (zed::Os::Windows, zed::Architecture::Aarch64) => Some("windows_arm64.zip"),
             _ => None,
         }
     }
 
+    fn binary_name_for_os(os: zed::Os) -> &'static str {
+        match os {
+            zed::Os::Windows => "shopware-lsp.exe",
+            _ => BINARY_NAME,
+        }
+    }
+
     fn server_path(
         &mut self,
         language_server_id: &zed::LanguageServerId,
         worktree: &zed::Worktree,
     ) -> zed::Result<String> {
+        let binary_settings = LspSettings::for_worktree(SERVER_NAME, worktree)
+            .ok()
+            .and_then(|lsp_settings| lsp_settings.binary);
+
+        if let Some(path) = binary_settings.as_ref().and_then(|b| b.path.clone()) {
+            return Ok(path);
+        }
+
         if let Some(ref path) = self.cached_binary_path {
             let p = std::path::Path::new(path);
             if p.exists() && p.is_file() {
@@ -31,10 +65,17 @@ impl ShopwareExtension {
             self.cached_binary_path = None;
         }
 
+        let (os, arch) = zed::current_platform();
+        let binary_name = Self::binary_name_for_os(os);
+
+        if let Some(path) = worktree.which(binary_name) {
+            return Ok(path);
+        }
+
         let root_path = worktree.root_path();
         let dev_paths = [
-            format!("{}/shopware-lsp", root_path),
-            format!("{}/../shopware-lsp/shopware-lsp", root_path),
+            format!("{root_path}/{binary_name}"),
+            format!("{root_path}/../shopware-lsp/{binary_name}"),
         ];
         for path in &dev_paths {
             let p = std::path::Path::new(path);
@@ -49,15 +90,128 @@ impl ShopwareExtension {
             &zed::LanguageServerInstallationStatus::CheckingForUpdate,
         );
 
-        let (os, arch) = zed::current_platform();
         let suffix = Self::asset_suffix_for_platform(os, arch)
             .ok_or_else(|| format!("Shopware LSP does not support {os:?} / {arch:?}"))?;
 
+        let (pre_release, pinned_version) = Self::release_settings(worktree);
+        let install = Self::plan_install(suffix, pre_release, pinned_version.as_deref())?;
+
+        let version_dir = format!("{BINARY_NAME}-{}", install.version);
+        let binary_path = format!("{version_dir}/{binary_name}");
+        let version_marker = format!("{version_dir}/.version");
+
+        let is_current = fs::read_to_string(&version_marker)
+            .map(|installed| installed == install.version)
+            .unwrap_or(false)
+            && fs::metadata(&binary_path).map_or(false, |stat| stat.is_file());
+
+        if !is_current {
+            zed::set_language_server_installation_status(
+                language_server_id,
+                &zed::LanguageServerInstallationStatus::Downloading,
+            );
+
+            // Touch the marker before downloading, not after: a sibling worktree's cleanup
+            // pass can run concurrently, and `version_dir_is_stale` treats a directory with
+            // no marker at all as a leftover partial install safe to delete. Without this,
+            // that pass could delete a version directory while its download is in flight.
+            fs::create_dir_all(&version_dir)
+                .map_err(|e| format!("Failed to create {version_dir}: {e}"))?;
+            fs::write(&version_marker, &install.version)
+                .map_err(|e| format!("Failed to write version marker: {e}"))?;
+
+            zed::download_file(&install.download_url, &version_dir, zed::DownloadedFileType::Zip)
+                .map_err(|e| {
+                    format!(
+                        "Failed to download shopware-lsp {} ({suffix}): {e}",
+                        install.version
+                    )
+                })?;
+
+            if os != zed::Os::Windows {
+                zed::make_file_executable(&binary_path)?;
+            }
+
+            // A fresh install is the only time it's worth paying for a directory scan to
+            // sweep out anything that's fallen stale.
+            Self::delete_obsolete_versions(&version_dir);
+        } else {
+            // Re-touch the marker so a version another worktree is still pinned to keeps
+            // looking "recently used" to `delete_obsolete_versions`, without that worktree
+            // having to resolve anything itself this run.
+            fs::write(&version_marker, &install.version)
+                .map_err(|e| format!("Failed to write version marker: {e}"))?;
+        }
+
+        self.cached_binary_path = Some(binary_path.clone());
+        Ok(binary_path)
+    }
+
+    fn release_settings(worktree: &zed::Worktree) -> (bool, Option<String>) {
+        let settings = LspSettings::for_worktree(SERVER_NAME, worktree)
+            .ok()
+            .and_then(|lsp_settings| lsp_settings.settings)
+            .unwrap_or_default();
+
+        let pre_release = settings
+            .get("releaseChannel")
+            .and_then(|v| v.as_str())
+            .map(|channel| channel == "nightly")
+            .unwrap_or(false);
+
+        let pinned_version = settings
+            .get("version")
+            .and_then(|v| v.as_str())
+            .map(str::to_string);
+
+        (pre_release, pinned_version)
+    }
+
+    /// A pinned `version` comes from worktree settings, which can be committed to the
+    /// project itself, so it must be constrained to a safe tag shape before it is used to
+    /// build the install directory name — otherwise an untrusted repo could smuggle path
+    /// separators or `..` segments into paths we read, write, and delete.
+    fn validate_pinned_version(tag: &str) -> zed::Result<()> {
+        let is_safe_tag =
+            !tag.is_empty() && tag.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+
+        if is_safe_tag {
+            Ok(())
+        } else {
+            Err(format!(
+                "Invalid pinned shopware-lsp version {tag:?}: must match [A-Za-z0-9._-]+"
+            ))
+        }
+    }
+
+    fn plan_install(
+        suffix: &str,
+        pre_release: bool,
+        pinned_version: Option<&str>,
+    ) -> zed::Result<PlannedInstall> {
+        if let Some(tag) = pinned_version {
+            Self::validate_pinned_version(tag)?;
+
+            let release = zed::github_release_by_tag_name(GITHUB_REPO, tag)
+                .map_err(|e| format!("Failed to fetch pinned release {tag}: {e}"))?;
+
+            let asset = release
+                .assets
+                .iter()
+                .find(|a| a.name.ends_with(suffix))
+                .ok_or_else(|| format!("No asset for {suffix} in release {tag}"))?;
+
+            return Ok(PlannedInstall {
+                version: tag.to_string(),
+                download_url: asset.download_url.clone(),
+            });
+        }
+
         let release = zed::latest_github_release(
             GITHUB_REPO,
             zed::GithubReleaseOptions {
                 require_assets: true,
-                pre_release: false,
+                pre_release,
             },
         )
         .map_err(|e| format!("Failed to fetch release: {e}"))?;
@@ -68,23 +222,67 @@ impl ShopwareExtension {
             .find(|a| a.name.ends_with(suffix))
             .ok_or_else(|| format!("No asset for {suffix} in release {}", release.version))?;
 
-        zed::set_language_server_installation_status(
-            language_server_id,
-            &zed::LanguageServerInstallationStatus::Downloading,
-        );
+        Ok(PlannedInstall {
+            version: release.version,
+            download_url: asset.download_url.clone(),
+        })
+    }
 
-        zed::download_file(
-            &asset.download_url,
-            &asset.name,
-            zed::DownloadedFileType::Zip,
-        )
-        .map_err(|e| format!("Failed to download: {e}"))?;
+    /// Removes installed version directories that haven't been resolved by any worktree
+    /// within `VERSION_RETENTION`. Age-based rather than an in-memory "currently open
+    /// worktrees" set, since that set can't survive an extension-host restart and would
+    /// otherwise treat an already-open sibling worktree as gone just because it hasn't
+    /// resolved its server path yet this process.
+    fn delete_obsolete_versions(current_version_dir: &str) {
+        let Ok(entries) = fs::read_dir(".") else {
+            return;
+        };
 
-        let binary_path = BINARY_NAME.to_string();
-        zed::make_file_executable(&binary_path)?;
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !name.starts_with(&format!("{BINARY_NAME}-")) || name == current_version_dir {
+                continue;
+            }
 
-        self.cached_binary_path = Some(binary_path.clone());
-        Ok(binary_path)
+            if Self::version_dir_is_stale(&entry.path()) {
+                fs::remove_dir_all(entry.path()).ok();
+            }
+        }
+    }
+
+    fn version_dir_is_stale(version_dir: &std::path::Path) -> bool {
+        let Ok(metadata) = fs::metadata(version_dir.join(".version")) else {
+            // No marker at all means a partial/failed install, not an in-use version.
+            return true;
+        };
+
+        let Ok(last_used) = metadata.modified() else {
+            return false;
+        };
+
+        SystemTime::now()
+            .duration_since(last_used)
+            .map_or(false, |age| age >= VERSION_RETENTION)
+    }
+
+    fn detect_project_root(root_path: &str) -> Option<String> {
+        let looks_like_shopware_project = fs::metadata(format!("{root_path}/composer.json"))
+            .map_or(false, |stat| stat.is_file())
+            || fs::metadata(format!("{root_path}/vendor"))
+                .map_or(false, |stat| stat.is_dir());
+
+        looks_like_shopware_project.then(|| root_path.to_string())
+    }
+
+    fn detect_extra_paths(root_path: &str) -> Vec<String> {
+        const CANDIDATES: &[&str] = &["custom/plugins", "custom/apps", "custom/static-plugins"];
+
+        CANDIDATES
+            .iter()
+            .map(|rel| format!("{root_path}/{rel}"))
+            .filter(|path| fs::metadata(path).map_or(false, |stat| stat.is_dir()))
+            .collect()
     }
 }
 
@@ -101,13 +299,70 @@ impl zed::Extension for ShopwareExtension {
         worktree: &zed::Worktree,
     ) -> zed::Result<zed::Command> {
         let server_path = self.server_path(language_server_id, worktree)?;
+
+        let binary_settings = LspSettings::for_worktree(SERVER_NAME, worktree)
+            .ok()
+            .and_then(|lsp_settings| lsp_settings.binary);
+        let args = binary_settings
+            .as_ref()
+            .and_then(|b| b.arguments.clone())
+            .unwrap_or_default();
+        let env = binary_settings
+            .and_then(|b| b.env.clone())
+            .unwrap_or_default();
+
         Ok(zed::Command {
             command: server_path,
-            args: vec![],
-            env: Default::default(),
+            args,
+            env,
         })
     }
 
+    fn language_server_workspace_configuration(
+        &mut self,
+        _language_server_id: &zed::LanguageServerId,
+        worktree: &zed::Worktree,
+    ) -> zed::Result<Option<zed::serde_json::Value>> {
+        let settings = LspSettings::for_worktree(SERVER_NAME, worktree)
+            .ok()
+            .and_then(|lsp_settings| lsp_settings.settings)
+            .unwrap_or_default();
+
+        let root_path = worktree.root_path();
+
+        let php_path = settings
+            .get("phpPath")
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .unwrap_or_else(|| "php".to_string());
+
+        let project_root = settings
+            .get("projectRoot")
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .or_else(|| Self::detect_project_root(&root_path))
+            .unwrap_or_else(|| root_path.clone());
+
+        let extra_paths = settings
+            .get("extraPaths")
+            .and_then(|v| v.as_array())
+            .map(|paths| {
+                paths
+                    .iter()
+                    .filter_map(|v| v.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_else(|| Self::detect_extra_paths(&project_root));
+
+        Ok(Some(zed::serde_json::json!({
+            "shopware": {
+                "phpPath": php_path,
+                "projectRoot": project_root,
+                "extraPaths": extra_paths,
+            }
+        })))
+    }
+
     fn run_slash_command(
         &self,
         command: zed::SlashCommand,